@@ -1,14 +1,22 @@
-use rustc_serialize::json::{Json, ToJson, ParserError};
+use backtrace::Backtrace;
+use rustc_serialize::base64::FromBase64Error;
+use rustc_serialize::json::{DecoderError, Json, ToJson, ParserError};
 use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt;
 use std::convert::From;
+use std::io;
+use std::sync::Mutex;
 use hyper::status::StatusCode;
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum ErrorStatus {
+    DetachedShadowRoot,
+    ElementClickIntercepted,
+    ElementNotInteractable,
     ElementNotSelectable,
     ElementNotVisible,
+    InsecureCertificate,
     InvalidArgument,
     InvalidCookieDomain,
     InvalidElementCoordinates,
@@ -18,8 +26,10 @@ pub enum ErrorStatus {
     JavascriptError,
     MoveTargetOutOfBounds,
     NoSuchAlert,
+    NoSuchCookie,
     NoSuchElement,
     NoSuchFrame,
+    NoSuchShadowRoot,
     NoSuchWindow,
     ScriptTimeout,
     SessionNotCreated,
@@ -38,7 +48,11 @@ pub type WebDriverResult<T> = Result<T, WebDriverError>;
 #[derive(Debug)]
 pub struct WebDriverError {
     pub status: ErrorStatus,
-    pub message: String
+    pub message: String,
+    pub stacktrace: Option<String>,
+    pub data: Option<Json>,
+    pub backtrace: Option<Mutex<Backtrace>>,
+    pub cause: Option<Box<dyn Error + Send + Sync>>,
 }
 
 impl fmt::Display for WebDriverError {
@@ -51,14 +65,76 @@ impl WebDriverError {
     pub fn new(status: ErrorStatus, message: &str) -> WebDriverError {
         WebDriverError {
             status: status,
-            message: message.to_string()
+            message: message.to_string(),
+            stacktrace: None,
+            data: None,
+            backtrace: Some(Mutex::new(Backtrace::new_unresolved())),
+            cause: None,
+        }
+    }
+
+    /// Like `new`, but skips capturing a backtrace. Use this on hot paths
+    /// where the cost of unwinding the stack isn't worth paying.
+    pub fn new_without_backtrace(status: ErrorStatus, message: &str) -> WebDriverError {
+        WebDriverError {
+            status: status,
+            message: message.to_string(),
+            stacktrace: None,
+            data: None,
+            backtrace: None,
+            cause: None,
+        }
+    }
+
+    pub fn new_with_stacktrace(status: ErrorStatus, message: &str, stacktrace: &str) -> WebDriverError {
+        WebDriverError {
+            status: status,
+            message: message.to_string(),
+            stacktrace: Some(stacktrace.to_string()),
+            data: None,
+            backtrace: None,
+            cause: None,
+        }
+    }
+
+    fn new_with_cause(status: ErrorStatus, cause: Box<dyn Error + Send + Sync>) -> WebDriverError {
+        let message = cause.to_string();
+        WebDriverError {
+            status: status,
+            message: message,
+            stacktrace: None,
+            data: None,
+            backtrace: Some(Mutex::new(Backtrace::new_unresolved())),
+            cause: Some(cause),
+        }
+    }
+
+    fn stacktrace(&self) -> String {
+        match self.stacktrace {
+            Some(ref stacktrace) => stacktrace.clone(),
+            None => match self.backtrace {
+                // Symbol resolution is the expensive part of capturing a
+                // backtrace, so it's deferred until the stacktrace is
+                // actually needed (e.g. for serialization), rather than
+                // paid unconditionally by every `WebDriverError`.
+                Some(ref backtrace) => {
+                    let mut backtrace = backtrace.lock().unwrap();
+                    backtrace.resolve();
+                    format!("{:?}", *backtrace)
+                }
+                None => "".to_string(),
+            }
         }
     }
 
     pub fn status_code(&self) -> &'static str {
         match self.status {
+            ErrorStatus::DetachedShadowRoot => "detached shadow root",
+            ErrorStatus::ElementClickIntercepted => "element click intercepted",
+            ErrorStatus::ElementNotInteractable => "element not interactable",
             ErrorStatus::ElementNotSelectable => "element not selectable",
             ErrorStatus::ElementNotVisible => "element not visible",
+            ErrorStatus::InsecureCertificate => "insecure certificate",
             ErrorStatus::InvalidArgument => "invalid argument",
             ErrorStatus::InvalidCookieDomain => "invalid cookie domain",
             ErrorStatus::InvalidElementCoordinates => "invalid element coordinates",
@@ -68,8 +144,10 @@ impl WebDriverError {
             ErrorStatus::JavascriptError => "javascript error",
             ErrorStatus::MoveTargetOutOfBounds => "move target out of bounds",
             ErrorStatus::NoSuchAlert => "no such alert",
+            ErrorStatus::NoSuchCookie => "no such cookie",
             ErrorStatus::NoSuchElement => "no such element",
             ErrorStatus::NoSuchFrame => "no such frame",
+            ErrorStatus::NoSuchShadowRoot => "no such shadow root",
             ErrorStatus::NoSuchWindow => "no such window",
             ErrorStatus::ScriptTimeout => "script timeout",
             ErrorStatus::SessionNotCreated => "session not created",
@@ -86,8 +164,12 @@ impl WebDriverError {
 
     pub fn http_status(&self) -> StatusCode {
         match self.status {
+            ErrorStatus::DetachedShadowRoot => StatusCode::BadRequest,
+            ErrorStatus::ElementClickIntercepted => StatusCode::BadRequest,
+            ErrorStatus::ElementNotInteractable => StatusCode::BadRequest,
             ErrorStatus::ElementNotSelectable => StatusCode::BadRequest,
             ErrorStatus::ElementNotVisible => StatusCode::BadRequest,
+            ErrorStatus::InsecureCertificate => StatusCode::InternalServerError,
             ErrorStatus::InvalidArgument => StatusCode::BadRequest,
             ErrorStatus::InvalidCookieDomain => StatusCode::BadRequest,
             ErrorStatus::InvalidElementCoordinates => StatusCode::BadRequest,
@@ -97,8 +179,10 @@ impl WebDriverError {
             ErrorStatus::JavascriptError => StatusCode::InternalServerError,
             ErrorStatus::MoveTargetOutOfBounds => StatusCode::InternalServerError,
             ErrorStatus::NoSuchAlert => StatusCode::BadRequest,
+            ErrorStatus::NoSuchCookie => StatusCode::BadRequest,
             ErrorStatus::NoSuchElement => StatusCode::NotFound,
             ErrorStatus::NoSuchFrame => StatusCode::BadRequest,
+            ErrorStatus::NoSuchShadowRoot => StatusCode::BadRequest,
             ErrorStatus::NoSuchWindow => StatusCode::BadRequest,
             ErrorStatus::ScriptTimeout => StatusCode::RequestTimeout,
             ErrorStatus::SessionNotCreated => StatusCode::InternalServerError,
@@ -108,7 +192,7 @@ impl WebDriverError {
             ErrorStatus::UnexpectedAlertOpen => StatusCode::InternalServerError,
             ErrorStatus::UnknownError => StatusCode::InternalServerError,
             ErrorStatus::UnknownPath => StatusCode::NotFound,
-            ErrorStatus::UnknownMethod => StatusCode::MethodNotAllowed,
+            ErrorStatus::UnknownMethod => StatusCode::NotFound,
             ErrorStatus::UnsupportedOperation => StatusCode::InternalServerError,
         }
     }
@@ -120,10 +204,17 @@ impl WebDriverError {
 
 impl ToJson for WebDriverError {
     fn to_json(&self) -> Json {
-        let mut data = BTreeMap::new();
-        data.insert("status".to_string(), self.status_code().to_json());
-        data.insert("message".to_string(), self.message.to_json());
-        Json::Object(data)
+        let mut value = BTreeMap::new();
+        value.insert("error".to_string(), self.status_code().to_json());
+        value.insert("message".to_string(), self.message.to_json());
+        value.insert("stacktrace".to_string(), self.stacktrace().to_json());
+        if let Some(ref data) = self.data {
+            value.insert("data".to_string(), data.clone());
+        }
+
+        let mut root = BTreeMap::new();
+        root.insert("value".to_string(), Json::Object(value));
+        Json::Object(root)
     }
 }
 
@@ -133,13 +224,232 @@ impl Error for WebDriverError {
     }
 
     fn cause(&self) -> Option<&Error> {
-        None
+        self.cause.as_ref().map(|e| e.as_ref() as &Error)
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.cause.as_ref().map(|e| e.as_ref() as &(dyn Error + 'static))
     }
 }
 
 impl From<ParserError> for WebDriverError {
     fn from(err: ParserError) -> WebDriverError {
-        let msg = format!("{:?}", err);
-        WebDriverError::new(ErrorStatus::UnknownError, &msg[..])
+        WebDriverError::new_with_cause(ErrorStatus::UnknownError, Box::new(err))
+    }
+}
+
+impl From<io::Error> for WebDriverError {
+    fn from(err: io::Error) -> WebDriverError {
+        WebDriverError::new_with_cause(ErrorStatus::UnknownError, Box::new(err))
+    }
+}
+
+impl From<DecoderError> for WebDriverError {
+    fn from(err: DecoderError) -> WebDriverError {
+        WebDriverError::new_with_cause(ErrorStatus::UnknownError, Box::new(err))
+    }
+}
+
+impl From<FromBase64Error> for WebDriverError {
+    fn from(err: FromBase64Error) -> WebDriverError {
+        WebDriverError::new_with_cause(ErrorStatus::InvalidArgument, Box::new(err))
+    }
+}
+
+/// Failure modes specific to establishing a new WebDriver session. Only
+/// `SessionNotCreated` carries a `WebDriverError` from the remote end; the
+/// other variants describe failures for which there is none.
+#[derive(Debug)]
+pub enum NewSessionError {
+    /// The configured WebDriver endpoint isn't a valid URL.
+    BadWebdriverUrl,
+    /// The underlying driver process or remote end could not be reached.
+    Failed,
+    /// The connection to the driver was established but dropped before a
+    /// session could be negotiated.
+    Lost,
+    /// The driver responded, but not with a conformant W3C payload (no
+    /// `value` envelope). The raw response is kept for diagnostics.
+    NotW3C(Json),
+    /// The driver explicitly refused to create a session.
+    SessionNotCreated(WebDriverError),
+}
+
+impl fmt::Display for NewSessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NewSessionError::BadWebdriverUrl => write!(f, "invalid webdriver url"),
+            NewSessionError::Failed => write!(f, "unable to start webdriver session"),
+            NewSessionError::Lost => write!(f, "webdriver session closed before establishment"),
+            NewSessionError::NotW3C(ref json) => {
+                write!(f, "webdriver response was not a conformant W3C response: {}", json)
+            }
+            NewSessionError::SessionNotCreated(ref err) => {
+                write!(f, "webdriver session not created: {}", err)
+            }
+        }
+    }
+}
+
+impl Error for NewSessionError {
+    fn description(&self) -> &str {
+        match *self {
+            NewSessionError::BadWebdriverUrl => "invalid webdriver url",
+            NewSessionError::Failed => "unable to start webdriver session",
+            NewSessionError::Lost => "webdriver session closed before establishment",
+            NewSessionError::NotW3C(_) => "webdriver response was not a conformant W3C response",
+            NewSessionError::SessionNotCreated(_) => "webdriver session not created",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            NewSessionError::SessionNotCreated(ref err) => Some(err),
+            _ => None,
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            NewSessionError::SessionNotCreated(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<NewSessionError> for WebDriverError {
+    fn from(err: NewSessionError) -> WebDriverError {
+        let message = err.to_string();
+        match err {
+            NewSessionError::SessionNotCreated(err) => err,
+            NewSessionError::NotW3C(json) => {
+                let mut webdriver_err = WebDriverError::new(ErrorStatus::SessionNotCreated, &message);
+                webdriver_err.data = Some(json);
+                webdriver_err
+            }
+            NewSessionError::BadWebdriverUrl |
+            NewSessionError::Failed |
+            NewSessionError::Lost => WebDriverError::new(ErrorStatus::SessionNotCreated, &message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_w3c_error_statuses_have_their_registered_codes_and_http_statuses() {
+        let cases = [
+            (ErrorStatus::DetachedShadowRoot, "detached shadow root", StatusCode::BadRequest),
+            (ErrorStatus::ElementClickIntercepted, "element click intercepted", StatusCode::BadRequest),
+            (ErrorStatus::ElementNotInteractable, "element not interactable", StatusCode::BadRequest),
+            (ErrorStatus::InsecureCertificate, "insecure certificate", StatusCode::InternalServerError),
+            (ErrorStatus::NoSuchCookie, "no such cookie", StatusCode::BadRequest),
+            (ErrorStatus::NoSuchShadowRoot, "no such shadow root", StatusCode::BadRequest),
+        ];
+        for (status, code, http_status) in cases.iter() {
+            let err = WebDriverError::new_without_backtrace(*status, "message");
+            assert_eq!(err.status_code(), *code);
+            assert_eq!(err.http_status(), *http_status);
+        }
+    }
+
+    #[test]
+    fn unknown_method_maps_to_not_found_like_unknown_path() {
+        // Both "unknown command" conditions now resolve to the same
+        // status code and HTTP status, per the W3C spec's unified
+        // "unknown command" semantics.
+        let unknown_path = WebDriverError::new_without_backtrace(ErrorStatus::UnknownPath, "message");
+        let unknown_method = WebDriverError::new_without_backtrace(ErrorStatus::UnknownMethod, "message");
+        assert_eq!(unknown_path.status_code(), unknown_method.status_code());
+        assert_eq!(unknown_method.http_status(), StatusCode::NotFound);
+    }
+
+    #[test]
+    fn new_captures_a_backtrace_that_serializes_to_a_non_empty_stacktrace() {
+        let err = WebDriverError::new(ErrorStatus::NoSuchElement, "not found");
+        let json = err.to_json();
+        let value = json.find("value").unwrap().as_object().unwrap();
+        assert_ne!(value.get("stacktrace").and_then(Json::as_string), Some(""));
+    }
+
+    #[test]
+    fn new_without_backtrace_serializes_to_an_empty_stacktrace() {
+        let err = WebDriverError::new_without_backtrace(ErrorStatus::NoSuchElement, "not found");
+        let json = err.to_json();
+        let value = json.find("value").unwrap().as_object().unwrap();
+        assert_eq!(value.get("stacktrace").and_then(Json::as_string), Some(""));
+    }
+
+    #[test]
+    fn new_with_stacktrace_is_not_overwritten_by_a_captured_backtrace() {
+        let err = WebDriverError::new_with_stacktrace(ErrorStatus::UnknownError, "boom", "line1\nline2");
+        let json = err.to_json();
+        let value = json.find("value").unwrap().as_object().unwrap();
+        assert_eq!(value.get("stacktrace").and_then(Json::as_string), Some("line1\nline2"));
+    }
+
+    fn assert_sync<T: Sync>() {}
+
+    #[test]
+    fn web_driver_error_is_sync() {
+        assert_sync::<WebDriverError>();
+    }
+
+    #[test]
+    fn to_json_wraps_errors_in_a_value_envelope() {
+        let err = WebDriverError::new_without_backtrace(ErrorStatus::NoSuchElement, "no such element found");
+        let json = err.to_json();
+        let value = json.find("value").expect("missing value envelope").as_object()
+            .expect("value is not an object");
+        assert_eq!(value.get("error").and_then(Json::as_string), Some("no such element"));
+        assert_eq!(value.get("message").and_then(Json::as_string), Some("no such element found"));
+        assert_eq!(value.get("stacktrace").and_then(Json::as_string), Some(""));
+        assert!(!value.contains_key("data"));
+    }
+
+    #[test]
+    fn to_json_includes_data_when_present() {
+        let mut err = WebDriverError::new_without_backtrace(ErrorStatus::SessionNotCreated, "boom");
+        err.data = Some(Json::Boolean(true));
+        let json = err.to_json();
+        let value = json.find("value").unwrap().as_object().unwrap();
+        assert_eq!(value.get("data"), Some(&Json::Boolean(true)));
+    }
+
+    #[test]
+    fn web_driver_error_source_follows_cause() {
+        let io_err = io::Error::new(io::ErrorKind::Other, "disconnected");
+        let err = WebDriverError::from(io_err);
+        assert!(err.source().is_some());
+        assert_eq!(err.status, ErrorStatus::UnknownError);
+    }
+
+    #[test]
+    fn new_session_error_source_only_for_session_not_created() {
+        let inner = WebDriverError::new_without_backtrace(ErrorStatus::SessionNotCreated, "refused");
+        let with_cause = NewSessionError::SessionNotCreated(inner);
+        assert!(with_cause.source().is_some());
+        assert!(with_cause.cause().is_some());
+
+        for err in vec![NewSessionError::BadWebdriverUrl, NewSessionError::Failed, NewSessionError::Lost] {
+            assert!(err.source().is_none());
+            assert!(err.cause().is_none());
+        }
+    }
+
+    #[test]
+    fn new_session_error_into_web_driver_error() {
+        let inner = WebDriverError::new_without_backtrace(ErrorStatus::SessionNotCreated, "refused");
+        let converted: WebDriverError = NewSessionError::SessionNotCreated(inner).into();
+        assert_eq!(converted.message, "refused");
+
+        let converted: WebDriverError = NewSessionError::NotW3C(Json::Boolean(false)).into();
+        assert_eq!(converted.status, ErrorStatus::SessionNotCreated);
+        assert_eq!(converted.data, Some(Json::Boolean(false)));
+
+        let converted: WebDriverError = NewSessionError::Lost.into();
+        assert_eq!(converted.status, ErrorStatus::SessionNotCreated);
     }
 }